@@ -31,6 +31,15 @@ pub fn matches(s: &ScanDir, entry: &DirEntry, name: &String)
     return Ok(true);
 }
 
+/// Sorts entries of a single directory in place using the comparator
+/// configured via `ScanDir::sort_by`, if any
+pub fn sort_entries(s: &ScanDir, entries: &mut Vec<DirEntry>) {
+    if let Some(ref cmp) = s.sort_by {
+        let mut cmp = cmp.borrow_mut();
+        entries.sort_by(|a, b| (&mut *cmp)(a, b));
+    }
+}
+
 pub fn name_matches(s: &ScanDir, name: &String) -> bool {
     if s.skip_hidden && name.starts_with(".") {
         return false;
@@ -1,8 +1,63 @@
 use std::fs::{read_dir, ReadDir, DirEntry};
+use std::io;
 use std::path::Path;
+use std::vec;
 
 use {Error, ScanDir};
-use filter::matches;
+use filter::{matches, sort_entries};
+
+/// A directory listing, read lazily from the OS unless a comparator is
+/// configured, in which case it is buffered into a `Vec` and sorted upfront
+///
+/// Keeping the lazy path around (instead of always collecting into a
+/// `Vec`) matters for large directories: `ScanDir::read()`/`walk()` should
+/// stream via `read_dir` for callers who never opted into `sort_by`.
+pub enum DirIter {
+    Raw(ReadDir),
+    Sorted(vec::IntoIter<io::Result<DirEntry>>),
+}
+
+impl Iterator for DirIter {
+    type Item = io::Result<DirEntry>;
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        match *self {
+            DirIter::Raw(ref mut dir) => dir.next(),
+            DirIter::Sorted(ref mut entries) => entries.next(),
+        }
+    }
+}
+
+/// Opens a directory, sorting it with the configured comparator if one is
+/// set
+///
+/// I/O errors on individual entries are recorded into `errors` rather than
+/// failing the whole read, matching how the rest of the crate reports
+/// failures. When no comparator is configured the directory is read
+/// lazily; sorting requires buffering the whole directory into memory
+/// first, so that only happens when `ScanDir::sort_by` is actually used.
+/// Buffering tolerates per-entry errors the same way the lazy path does:
+/// only the successfully read entries are sorted, and any errors are
+/// yielded afterwards for the caller to report.
+pub fn open_dir(settings: &ScanDir, path: &Path) -> io::Result<DirIter> {
+    let dir = try!(read_dir(path));
+    if settings.sort_by.is_some() {
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        for res in dir {
+            match res {
+                Ok(entry) => entries.push(entry),
+                Err(e) => errors.push(e),
+            }
+        }
+        sort_entries(settings, &mut entries);
+        let items = entries.into_iter().map(Ok)
+            .chain(errors.into_iter().map(Err))
+            .collect::<Vec<_>>();
+        Ok(DirIter::Sorted(items.into_iter()))
+    } else {
+        Ok(DirIter::Raw(dir))
+    }
+}
 
 /// Iterator over pairs of (DirEntry, String) where latter is the file name
 ///
@@ -14,19 +69,19 @@ pub struct Iter<'a> {
     settings: &'a ScanDir,
     error: &'a mut Result<(), Error>,
     path: &'a Path,
-    iter: Option<ReadDir>,
+    iter: Option<DirIter>,
 }
 
-pub fn new<'x>(settings: &'x ScanDir, error: &'x mut Result<(), Error>,
+pub fn new<'x>(settings: &'x ScanDir, err: &'x mut Result<(), Error>,
     path: &'x Path)
     -> Iter<'x>
 {
-    let iter = read_dir(path).map_err(|e| {
-        *error = Err(Error::Io(e, path.to_path_buf()));
+    let iter = open_dir(settings, path).map_err(|e| {
+        *err = Err(Error::Io(e, path.to_path_buf()));
     }).ok();
     Iter {
         settings: settings,
-        error: error,
+        error: err,
         path: path,
         iter: iter,
     }
@@ -105,8 +105,13 @@
 //!
 #[macro_use] extern crate quick_error;
 
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::DirEntry;
 use std::io;
 use std::path::{PathBuf, Path};
+use std::rc::Rc;
 
 mod iter;
 mod walk;
@@ -138,17 +143,55 @@ quick_error! {
             display("error decoding file name {:?}", path)
             description("error decoding file name")
         }
+        /// A symlink loop was found while following links
+        ///
+        /// `ancestor` is the directory already on the walk stack that
+        /// `child` (a symlink) resolves back to
+        Loop(ancestor: PathBuf, child: PathBuf) {
+            display("filesystem loop found: {:?} points back to {:?}",
+                child, ancestor)
+            description("filesystem loop found")
+        }
     }
 }
 
+/// A user-supplied comparator used to sort entries within each directory
+type Comparator = Rc<RefCell<Box<FnMut(&DirEntry, &DirEntry) -> Ordering>>>;
+
 /// Settings for directory walker
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ScanDir {
     skip_hidden: bool,
     skip_dirs: bool,
     skip_files: bool,
     skip_backup: bool,
     skip_symlinks: bool,
+    min_depth: usize,
+    max_depth: usize,
+    follow_links: bool,
+    contents_first: bool,
+    sort_by: Option<Comparator>,
+    max_open: usize,
+    same_file_system: bool,
+}
+
+impl fmt::Debug for ScanDir {
+    fn fmt(&self, fmtr: &mut fmt::Formatter) -> fmt::Result {
+        fmtr.debug_struct("ScanDir")
+            .field("skip_hidden", &self.skip_hidden)
+            .field("skip_dirs", &self.skip_dirs)
+            .field("skip_files", &self.skip_files)
+            .field("skip_backup", &self.skip_backup)
+            .field("skip_symlinks", &self.skip_symlinks)
+            .field("min_depth", &self.min_depth)
+            .field("max_depth", &self.max_depth)
+            .field("follow_links", &self.follow_links)
+            .field("contents_first", &self.contents_first)
+            .field("sort_by", &self.sort_by.is_some())
+            .field("max_open", &self.max_open)
+            .field("same_file_system", &self.same_file_system)
+            .finish()
+    }
 }
 
 impl ScanDir {
@@ -162,6 +205,13 @@ impl ScanDir {
             skip_files: false,
             skip_backup: false,
             skip_symlinks: false,
+            min_depth: 0,
+            max_depth: ::std::usize::MAX,
+            follow_links: false,
+            contents_first: false,
+            sort_by: None,
+            max_open: ::std::usize::MAX,
+            same_file_system: false,
         }
     }
     /// Constructs a settings which only iterates over files (non-directories).
@@ -174,6 +224,13 @@ impl ScanDir {
             skip_files: false,
             skip_backup: true,
             skip_symlinks: false,
+            min_depth: 0,
+            max_depth: ::std::usize::MAX,
+            follow_links: false,
+            contents_first: false,
+            sort_by: None,
+            max_open: ::std::usize::MAX,
+            same_file_system: false,
         }
     }
     /// Constructs a settings which only iterates over directories
@@ -186,6 +243,13 @@ impl ScanDir {
             skip_files: true,
             skip_backup: true,
             skip_symlinks: false,
+            min_depth: 0,
+            max_depth: ::std::usize::MAX,
+            follow_links: false,
+            contents_first: false,
+            sort_by: None,
+            max_open: ::std::usize::MAX,
+            same_file_system: false,
         }
     }
 
@@ -244,6 +308,109 @@ impl ScanDir {
         self
     }
 
+    /// Set the minimum depth of entries yielded by the recursive walker
+    ///
+    /// Entries shallower than `min_depth` are still traversed (so their
+    /// children are reached), but are not yielded themselves. Entries
+    /// found directly in the directory passed to `walk()` are depth zero.
+    /// Only affects `walk()`, has no effect on `read()`.
+    pub fn min_depth(&mut self, depth: usize) -> &mut ScanDir {
+        self.min_depth = depth;
+        self
+    }
+
+    /// Set the maximum depth of entries yielded by the recursive walker
+    ///
+    /// Directories at exactly `max_depth` are yielded but the walker does
+    /// not descend into them. Entries found directly in the directory
+    /// passed to `walk()` are depth zero. Only affects `walk()`, has no
+    /// effect on `read()`.
+    pub fn max_depth(&mut self, depth: usize) -> &mut ScanDir {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Follow symlinks to directories while recursively walking
+    ///
+    /// By default the recursive walker never descends into a symlink,
+    /// even if it points to a directory. When this is enabled, symlinked
+    /// directories are resolved and descended into just like real ones.
+    ///
+    /// Descending into a symlink that points back at one of its own
+    /// ancestor directories would recurse forever, so each followed
+    /// symlink's target is checked against every directory currently
+    /// being walked; if it matches, a `Error::Loop` is recorded instead
+    /// of recursing. Only affects `walk()`, has no effect on `read()`.
+    pub fn follow_links(&mut self, flag: bool) -> &mut ScanDir {
+        self.follow_links = flag;
+        self
+    }
+
+    /// Yield directories after their contents rather than before
+    ///
+    /// By default the recursive walker yields a directory entry as soon
+    /// as it is found, before descending into it. When this is enabled,
+    /// a directory is instead yielded right after all of its descendants
+    /// have been yielded. This is what recursive delete or size-rollup
+    /// style operations need. Only affects `walk()`, has no effect on
+    /// `read()`.
+    pub fn contents_first(&mut self, flag: bool) -> &mut ScanDir {
+        self.contents_first = flag;
+        self
+    }
+
+    /// Sort entries within each directory using a custom comparator
+    ///
+    /// By default entries are yielded in whatever order the operating
+    /// system's `read_dir` returns them, which is arbitrary and may
+    /// differ between runs and platforms. When a comparator is set, the
+    /// entries of each directory are collected and sorted with it before
+    /// being yielded (or, for `walk()`, before being descended into).
+    /// Affects both `read()` and `walk()`.
+    pub fn sort_by<F>(&mut self, cmp: F) -> &mut ScanDir
+        where F: FnMut(&DirEntry, &DirEntry) -> Ordering + 'static
+    {
+        self.sort_by = Some(Rc::new(RefCell::new(Box::new(cmp))));
+        self
+    }
+
+    /// Sort entries within each directory by file name
+    ///
+    /// A convenience wrapper around `sort_by` for the common case of
+    /// wanting a deterministic, alphabetically sorted traversal.
+    pub fn sort_by_name(&mut self) -> &mut ScanDir {
+        self.sort_by(|a, b| a.file_name().cmp(&b.file_name()))
+    }
+
+    /// Bound how many ancestor directories the recursive walker keeps open
+    ///
+    /// `Walker` keeps the entries of every ancestor directory of the
+    /// current path around so it can resume each one once it is done
+    /// descending into its children. On a very deep tree this can add up.
+    /// When set, once more than `max` directories are held at once, the
+    /// walker closes the ones nearest the root (forgetting their entries
+    /// but remembering how many had already been read) and transparently
+    /// reopens and fast-forwards them once the walk climbs back up to
+    /// them. Only affects `walk()`, has no effect on `read()`.
+    pub fn max_open(&mut self, max: usize) -> &mut ScanDir {
+        self.max_open = max;
+        self
+    }
+
+    /// Do not descend into directories on a different filesystem
+    ///
+    /// By default the recursive walker follows directories regardless of
+    /// which filesystem they belong to. When this is enabled, a directory
+    /// is still yielded but not descended into if its device id differs
+    /// from that of the root directory passed to `walk()`. This mirrors
+    /// `find -xdev`, and is useful to keep a scan of `/` from wandering
+    /// into `/proc`, network mounts, and the like. Only affects `walk()`,
+    /// has no effect on `read()`.
+    pub fn same_file_system(&mut self, flag: bool) -> &mut ScanDir {
+        self.same_file_system = flag;
+        self
+    }
+
     /// Calls a closure with an iterator over pairs of (entry, name)
     ///
     /// Note when it comes to error reporting, here is how errors are
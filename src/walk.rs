@@ -1,14 +1,89 @@
+use std::io;
 use std::mem::replace;
-use std::fs::{read_dir, ReadDir, DirEntry};
+use std::fs::{metadata, DirEntry, Metadata};
 use std::path::{Path, PathBuf};
 
 use filter::name_matches;
+use iter::{DirIter, open_dir as open};
 use {Error, ScanDir};
 
+#[cfg(unix)]
+fn file_identity(meta: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+#[cfg(windows)]
+fn file_identity(meta: &Metadata) -> (u64, u64) {
+    use std::os::windows::fs::MetadataExt;
+    (meta.volume_serial_number().unwrap_or(0) as u64,
+     meta.file_index().unwrap_or(0))
+}
+
+type Frame = (DirIter, PathBuf, usize, Option<(u64, u64)>,
+    Option<(DirEntry, String)>, usize);
+
+/// An ancestor directory on the walk stack, either still open (holding its
+/// entries in memory) or closed to respect `ScanDir::max_open`
+///
+/// A closed frame remembers everything needed to reopen and fast-forward
+/// the directory once the walk climbs back up to it: its path and how
+/// many of its entries had already been consumed, plus the bits
+/// `find_ancestor` needs to keep detecting symlink loops while it is
+/// closed.
+enum StackFrame {
+    Open(Frame),
+    Closed {
+        path: PathBuf,
+        depth: usize,
+        ident: Option<(u64, u64)>,
+        pending: Option<(DirEntry, String)>,
+        consumed: usize,
+    },
+}
+
+impl StackFrame {
+    fn is_open(&self) -> bool {
+        match *self {
+            StackFrame::Open(..) => true,
+            StackFrame::Closed { .. } => false,
+        }
+    }
+    fn path(&self) -> &Path {
+        match *self {
+            StackFrame::Open((_, ref path, _, _, _, _)) => path,
+            StackFrame::Closed { ref path, .. } => path,
+        }
+    }
+    fn ident(&self) -> Option<(u64, u64)> {
+        match *self {
+            StackFrame::Open((_, _, _, ident, _, _)) => ident,
+            StackFrame::Closed { ident, .. } => ident,
+        }
+    }
+    /// Turns an open frame into a closed one, remembering how many of
+    /// its entries have already been consumed
+    fn close(self) -> StackFrame {
+        match self {
+            StackFrame::Open((_, path, depth, ident, pending, consumed)) => {
+                StackFrame::Closed {
+                    path: path,
+                    depth: depth,
+                    ident: ident,
+                    pending: pending,
+                    consumed: consumed,
+                }
+            }
+            closed => closed,
+        }
+    }
+}
+
 /// Iterator over pairs of (DirEntry, String) where latter is the file name
 ///
-/// Iterator walks over files/directories in the depth-first order and doesn't
-/// sort items any way. Only utf-8 decodable directory names are visited.
+/// Iterator walks over files/directories in the depth-first order. Unless
+/// `ScanDir::sort_by` is used, items within a single directory are visited
+/// in arbitrary order. Only utf-8 decodable directory names are visited.
 /// Same rules applied to both files and directories. If you want more
 /// control, you may either filter files in the iterator itself or walk over
 /// directory tree and use `ScanDir::read()` over files in each directory.
@@ -20,22 +95,222 @@ use {Error, ScanDir};
 pub struct Walker<'a> {
     settings: &'a ScanDir,
     errors: &'a mut Vec<Error>,
-    cur: Option<(ReadDir, PathBuf)>,
-    stack: Vec<(ReadDir, PathBuf)>,
+    cur: Option<Frame>,
+    stack: Vec<StackFrame>,
+    root_device: Option<u64>,
+}
+
+fn identity_of(settings: &ScanDir, path: &Path) -> Option<(u64, u64)> {
+    if !settings.follow_links {
+        return None;
+    }
+    metadata(path).ok().map(|m| file_identity(&m))
+}
+
+/// The device id of a directory, for `ScanDir::same_file_system`
+fn device_of(path: &Path) -> Option<u64> {
+    metadata(path).ok().map(|m| file_identity(&m).0)
+}
+
+/// Opens a directory for the walker, recording the I/O error (if any)
+/// the same way the rest of the walker reports failures
+fn open_dir(settings: &ScanDir, errors: &mut Vec<Error>, path: &Path)
+    -> Option<DirIter>
+{
+    match open(settings, path) {
+        Ok(iter) => Some(iter),
+        Err(e) => {
+            errors.push(Error::Io(e, path.to_path_buf()));
+            None
+        }
+    }
 }
 
 pub fn new<'x>(settings: &'x ScanDir, errors: &'x mut Vec<Error>,
     path: &'x Path)
     -> Walker<'x>
 {
-    let iter = read_dir(path).map_err(|e| {
-        errors.push(Error::Io(e, path.to_path_buf()));
-    }).ok().map(|i| (i, path.to_path_buf()));
+    let ident = identity_of(settings, path);
+    let root_device = if settings.same_file_system {
+        device_of(path)
+    } else {
+        None
+    };
+    let cur = open_dir(settings, errors, path)
+        .map(|iter| (iter, path.to_path_buf(), 0, ident, None, 0));
     Walker {
         settings: settings,
         errors: errors,
-        cur: iter,
+        cur: cur,
         stack: Vec::new(),
+        root_device: root_device,
+    }
+}
+
+/// Finds the path of the current directory or of an ancestor on the walk
+/// stack (open or closed) identified by `ident`, if any, for use in a
+/// `Error::Loop`
+fn find_ancestor(cur_path: &Path, cur_ident: Option<(u64, u64)>,
+    stack: &[StackFrame], ident: (u64, u64))
+    -> Option<PathBuf>
+{
+    if cur_ident == Some(ident) {
+        return Some(cur_path.to_path_buf());
+    }
+    for frame in stack {
+        if frame.ident() == Some(ident) {
+            return Some(frame.path().to_path_buf());
+        }
+    }
+    None
+}
+
+/// What a freshly-read directory entry turned out to be
+///
+/// A symlink that is being followed resolves to either variant depending
+/// on its target; its metadata is carried along so callers don't have to
+/// stat it again.
+enum EntryKind {
+    File,
+    Dir(Option<Metadata>),
+}
+
+/// Determines whether an entry is a file or a directory, resolving a
+/// symlink's target when `ScanDir::follow_links` is set
+fn classify_entry(settings: &ScanDir, entry: &DirEntry)
+    -> io::Result<EntryKind>
+{
+    let typ = try!(entry.file_type());
+    if typ.is_symlink() && settings.follow_links {
+        let meta = try!(metadata(entry.path()));
+        if meta.file_type().is_dir() {
+            return Ok(EntryKind::Dir(Some(meta)));
+        }
+        return Ok(EntryKind::File);
+    }
+    if typ.is_dir() {
+        Ok(EntryKind::Dir(None))
+    } else {
+        Ok(EntryKind::File)
+    }
+}
+
+/// Whether a directory entry should be yielded and/or descended into,
+/// given depth and filesystem bounds
+///
+/// Loop detection is handled separately by `find_ancestor`, since it
+/// needs the ancestor stack rather than just the entry itself.
+struct DirBounds {
+    should_yield: bool,
+    can_descend: bool,
+}
+
+fn dir_bounds(settings: &ScanDir, root_device: Option<u64>, depth: usize,
+    entry_path: &Path, target_meta: &Option<Metadata>)
+    -> DirBounds
+{
+    let crosses_fs = settings.same_file_system &&
+        root_device.map_or(false, |root| {
+            let dev = match *target_meta {
+                Some(ref meta) => Some(file_identity(meta).0),
+                None => device_of(entry_path),
+            };
+            dev.map_or(false, |d| d != root)
+        });
+    DirBounds {
+        should_yield: !settings.skip_dirs && depth >= settings.min_depth,
+        can_descend: depth < settings.max_depth && !crosses_fs,
+    }
+}
+
+/// What to do with a directory entry once it's known whether the walker
+/// is descending into it
+enum YieldPlan {
+    /// Stash it to be yielded once its descendants have been, for
+    /// `ScanDir::contents_first`
+    Stash,
+    /// Yield it right away
+    Now,
+    /// Neither; it's below `ScanDir::min_depth` or `ScanDir::skip_dirs`
+    /// is set
+    Never,
+}
+
+fn yield_plan(contents_first: bool, should_yield: bool) -> YieldPlan {
+    if !should_yield {
+        YieldPlan::Never
+    } else if contents_first {
+        YieldPlan::Stash
+    } else {
+        YieldPlan::Now
+    }
+}
+
+impl<'a> Walker<'a> {
+    /// Prevent descending into the directory that was just yielded
+    ///
+    /// By default, once a directory entry that the walker descends into
+    /// has been yielded, the walker has already opened it and will read
+    /// its entries on the next call to `next()`. Calling this method right
+    /// after such a directory is yielded discards that already-opened
+    /// directory, so its contents are never read and none of its
+    /// descendants are visited. This is much cheaper than letting the walk
+    /// continue and filtering the descendants out afterwards; the common
+    /// use is pruning a `.git` or `target` subtree as soon as it is seen.
+    ///
+    /// The method always discards the remaining entries of whatever
+    /// directory is current. So when it is called at any other time (e.g.
+    /// when the last yielded item was a file, a directory that was not
+    /// descended into, a directory yielded under `contents_first`, or
+    /// twice in a row), it is the rest of the enclosing directory that is
+    /// skipped.
+    pub fn skip_current_dir(&mut self) {
+        self.cur = self.pop_cur();
+    }
+
+    /// Pops the next frame off the stack, transparently reopening and
+    /// fast-forwarding it if it had been closed to respect `max_open`
+    fn pop_cur(&mut self) -> Option<Frame> {
+        match self.stack.pop() {
+            Some(StackFrame::Open(frame)) => Some(frame),
+            Some(StackFrame::Closed { path, depth, ident, pending,
+                consumed }) =>
+            {
+                match open_dir(self.settings, self.errors, &path) {
+                    Some(mut iter) => {
+                        for _ in 0..consumed {
+                            if iter.next().is_none() {
+                                break;
+                            }
+                        }
+                        Some((iter, path, depth, ident, pending, consumed))
+                    }
+                    None => self.pop_cur(),
+                }
+            }
+            None => None,
+        }
+    }
+
+}
+
+/// Closes the open ancestor frame nearest the root, if any, so the number
+/// of simultaneously open directories stays within `settings.max_open`
+///
+/// Takes `stack` by reference rather than being a `Walker` method so it
+/// can be called while `self.cur` is already borrowed apart.
+fn enforce_max_open(settings: &ScanDir, stack: &mut Vec<StackFrame>) {
+    loop {
+        let open_count = 1 + stack.iter().filter(|f| f.is_open()).count();
+        if open_count <= settings.max_open {
+            break;
+        }
+        let idx = match stack.iter().position(|f| f.is_open()) {
+            Some(idx) => idx,
+            None => break,
+        };
+        let frame = stack.remove(idx);
+        stack.insert(idx, frame.close());
     }
 }
 
@@ -43,68 +318,141 @@ impl<'a> Iterator for Walker<'a> {
     type Item = (DirEntry, String);
     fn next(&mut self) -> Option<(DirEntry, String)> {
         loop {
-            if let Some((ref mut iter, ref mut path)) = self.cur {
+            let mut exhausted = false;
+            if let Some((ref mut iter, ref mut path, ref mut depth_slot,
+                         ref mut ident_slot, ref mut pending_slot,
+                         ref mut consumed_slot)) = self.cur
+            {
+                let depth = *depth_slot;
                 match iter.next() {
                     Some(Ok(entry)) => {
+                        *consumed_slot += 1;
                         let osname = entry.file_name();
                         if let Ok(name) = osname.into_string() {
                             if !name_matches(self.settings, &name) {
                                 continue;
                             }
-                            let typ = match entry.file_type() {
-                                Ok(typ) => typ,
+                            let kind = match classify_entry(self.settings,
+                                &entry)
+                            {
+                                Ok(kind) => kind,
                                 Err(e) => {
                                     self.errors.push(
                                         Error::Io(e, entry.path()));
                                     continue;
                                 }
                             };
-                            if typ.is_dir() {
-                                let new_path = entry.path();
-                                match read_dir(&new_path) {
-                                    Ok(new_iter) => {
-                                        let old_iter = replace(iter, new_iter);
-                                        let old_path = replace(path, new_path);
-                                        self.stack.push((old_iter, old_path));
-
+                            let target_meta = match kind {
+                                EntryKind::File => {
+                                    if !self.settings.skip_files &&
+                                        depth >= self.settings.min_depth
+                                    {
+                                        return Some((entry, name));
                                     }
-                                    Err(e) => {
-                                        self.errors.push(
-                                            Error::Io(e, entry.path()));
-                                    }
-                                }
-                                if !self.settings.skip_dirs {
-                                    return Some((entry, name));
+                                    continue;
                                 }
-                            } else {
-                                if !self.settings.skip_files {
-                                    return Some((entry, name));
+                                EntryKind::Dir(target_meta) => target_meta,
+                            };
+                            let bounds = dir_bounds(self.settings,
+                                self.root_device, depth, &entry.path(),
+                                &target_meta);
+                            if bounds.can_descend {
+                                let is_loop = match target_meta {
+                                    Some(ref meta) => find_ancestor(
+                                        path.as_path(), *ident_slot,
+                                        &self.stack, file_identity(meta)),
+                                    None => None,
+                                };
+                                if let Some(ancestor) = is_loop {
+                                    self.errors.push(Error::Loop(
+                                        ancestor, entry.path()));
+                                } else {
+                                    let new_path = entry.path();
+                                    let opened = open_dir(self.settings,
+                                        self.errors, &new_path);
+                                    if let Some(new_iter) = opened {
+                                        let new_ident = match target_meta {
+                                            Some(ref meta) =>
+                                                Some(file_identity(meta)),
+                                            None => identity_of(
+                                                self.settings, &new_path),
+                                        };
+                                        // `entry`/`name` are moved into
+                                        // at most one of `new_pending`
+                                        // (stashed for `contents_first`)
+                                        // or `result` (yielded now),
+                                        // never both, so there is no
+                                        // later use-after-move.
+                                        let plan = yield_plan(
+                                            self.settings.contents_first,
+                                            bounds.should_yield);
+                                        let (new_pending, result) =
+                                            match plan {
+                                                YieldPlan::Stash =>
+                                                    (Some((entry, name)),
+                                                        None),
+                                                YieldPlan::Now =>
+                                                    (None,
+                                                        Some((entry, name))),
+                                                YieldPlan::Never =>
+                                                    (None, None),
+                                            };
+                                        let old_iter =
+                                            replace(iter, new_iter);
+                                        let old_path =
+                                            replace(path, new_path);
+                                        let old_ident =
+                                            replace(ident_slot, new_ident);
+                                        let old_pending =
+                                            replace(pending_slot,
+                                                new_pending);
+                                        let old_consumed =
+                                            replace(consumed_slot, 0);
+                                        self.stack.push(StackFrame::Open((
+                                            old_iter, old_path, depth,
+                                            old_ident, old_pending,
+                                            old_consumed)));
+                                        *depth_slot = depth + 1;
+                                        enforce_max_open(self.settings,
+                                            &mut self.stack);
+                                        if self.settings.contents_first {
+                                            continue;
+                                        }
+                                        if let Some(pair) = result {
+                                            return Some(pair);
+                                        }
+                                        continue;
+                                    }
                                 }
                             }
+                            if bounds.should_yield {
+                                return Some((entry, name));
+                            }
                         } else {
                             self.errors.push(
                                 Error::Decode(entry.path()));
                         }
                     }
                     Some(Err(e)) => {
-                        self.errors.push(
-                            Error::Io(e, path.to_path_buf()));
+                        *consumed_slot += 1;
+                        self.errors.push(Error::Io(e, path.clone()));
                     }
                     None => {
-                        if let Some((new_iter, new_path)) = self.stack.pop() {
-                            *iter = new_iter;
-                            *path = new_path;
-                        } else {
-                            break;
+                        if let Some(pending) = pending_slot.take() {
+                            return Some(pending);
                         }
+                        exhausted = true;
                     }
                 }
             } else {
                 return None
             }
+            if exhausted {
+                self.cur = self.pop_cur();
+                if self.cur.is_none() {
+                    return None;
+                }
+            }
         }
-        // We can only clean self.cur here because of borrowing rules
-        self.cur = None;
-        return None;
     }
 }